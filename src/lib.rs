@@ -5,10 +5,19 @@ use std::result::Result::{Ok, Err};
 use mdbook::errors::Error;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
 use std::time::SystemTime;
 
+/// Directives resolved from a regex match, keyed by the directive's full matched text, so
+/// later passes can reuse what an earlier pass already parsed instead of re-parsing (and,
+/// for `page="all"`, re-reading the source `.drawio` file) on every match.
+type Resolved = HashMap<String, Vec<Directive>>;
+
 pub struct DrawioPreprocessor;
 
 impl Preprocessor for DrawioPreprocessor {
@@ -21,80 +30,147 @@ impl Preprocessor for DrawioPreprocessor {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        fn process_item(ctx: &PreprocessorContext, item: &mut BookItem) -> Result<(), Error> {
-            match item {
-                BookItem::Chapter(ch) => process_chapter(ctx, ch),
-                _ => Ok(()),
+        // Phase 1: walk every chapter once to collect the set of unique export jobs the
+        // whole book needs, keyed by output filename so a diagram referenced from
+        // multiple chapters is only exported once.
+        fn collect_item(
+            ctx: &PreprocessorContext,
+            item: &BookItem,
+            jobs: &mut HashMap<String, (Directive, PathBuf, PathBuf)>,
+            resolved: &mut Resolved,
+        ) {
+            if let BookItem::Chapter(ch) = item {
+                collect_chapter(ctx, ch, jobs, resolved);
             }
         }
 
-        // How we process a regex match
-        fn process_match(
+        fn collect_chapter(
             ctx: &PreprocessorContext,
             ch: &Chapter,
-            caps: &regex::Captures,
-        ) -> String {
-            debug!("Processing regex match: {caps:?}");
-            let relative_path = caps.get(1).map(|m| m.as_str()).unwrap();
-            debug!("  Relative path: {relative_path}");
-
-            let page = caps
-                .get(2)
-                .and_then(|m| m.as_str().parse::<u32>().ok())
-                .unwrap();
-            debug!("  Page: {page}");
-
-            let absolute_path = ctx.root.join(relative_path);
-            debug!("  Absolute path: {absolute_path:?}");
-
-            let diagram_name = absolute_path.file_stem().and_then(|s| s.to_str()).unwrap();
-            debug!("  Diagram name: {diagram_name}");
-
-            let svg_name = format!("{}-page-{}.svg", diagram_name, page);
-            debug!("  SVG filename: {svg_name}");
-
-            let result_dir = get_result_dir_abs(&ctx);
-            std::fs::create_dir_all(&result_dir).ok();
-
-            let svg_path = &result_dir.join(&svg_name);
-            debug!("  SVG path: {svg_path:?}");
-
-            let svg_relative_path = relative_path_from_chapter(ctx, &ch, &svg_path);
-            debug!("  Relative link from chapter: {svg_relative_path:?}");
-
-            // Export the diagram only if needed (cache check)
-            if should_generate(&absolute_path, &svg_path) {
-                debug!("  Cache miss or outdated - regenerating diagram");
-                drawio_export(ctx, &absolute_path, page, &svg_path).ok();
-            } else {
-                debug!("  Cache hit - reusing existing SVG");
+            jobs: &mut HashMap<String, (Directive, PathBuf, PathBuf)>,
+            resolved: &mut Resolved,
+        ) {
+            let re = directive_regex();
+            for caps in re.captures_iter(&ch.content) {
+                let full_match = caps.get(0).map(|m| m.as_str()).unwrap_or_default().to_string();
+                let directives = resolved
+                    .entry(full_match)
+                    .or_insert_with(|| Directive::parse_many(ctx, &caps));
+                if directives.is_empty() {
+                    error!("Failed to parse drawio directive: {caps:?}");
+                    continue;
+                }
+                for directive in directives.clone() {
+                    // `output_name` encodes the full render identity (page, format, scale,
+                    // width, height - see `diagram_paths`), so this correctly dedups two
+                    // directives for the same page/format only when they'd render
+                    // identically; directives that differ in scale/width/height get their
+                    // own job instead of colliding.
+                    let (absolute_path, output_name, output_path) = diagram_paths(ctx, &directive);
+                    jobs.entry(output_name)
+                        .or_insert((directive, absolute_path, output_path));
+                }
             }
+            for sub in &ch.sub_items {
+                collect_item(ctx, sub, jobs, resolved);
+            }
+        }
 
-            // Create a Markdown snippet for the SVG
-            let snippet = format!(
-                "![Diagram not found at {}]({})",
-                &svg_relative_path.display(),
-                &svg_relative_path.display()
-            );
-            log::debug!("Produced Markdown snippet for SVG: {snippet}");
-            snippet
+        let mut jobs: HashMap<String, (Directive, PathBuf, PathBuf)> = HashMap::new();
+        let mut resolved: Resolved = HashMap::new();
+        for item in book.sections.iter() {
+            collect_item(ctx, item, &mut jobs, &mut resolved);
+        }
+
+        // Phase 2: figure out which jobs are stale (content-hash cache check) and render
+        // those concurrently with a worker pool bounded by `preprocessor.drawio.jobs`.
+        let result_dir = get_result_dir_abs(ctx);
+        std::fs::create_dir_all(&result_dir).ok();
+        let manifest = Mutex::new(load_manifest(&result_dir));
+
+        let stale: Vec<(String, Directive, PathBuf, PathBuf, String)> = jobs
+            .into_iter()
+            .filter_map(|(key, (directive, input, output))| {
+                let (regenerate, hash) = {
+                    let manifest = manifest.lock().unwrap();
+                    should_generate(&input, &output, &key, &directive, &manifest)
+                };
+                regenerate.then_some((key, directive, input, output, hash))
+            })
+            .collect();
+
+        if !stale.is_empty() {
+            let workers = get_jobs(ctx).max(1);
+            let drawio_bin = get_drawio_bin(ctx).to_string();
+            debug!("Rendering {} diagram(s) with {workers} worker(s)", stale.len());
+
+            // A shared work queue rather than fixed chunks: each worker pulls the next
+            // job as soon as it's free, so one slow export only stalls itself instead of
+            // the whole next batch.
+            let next_job = std::sync::atomic::AtomicUsize::new(0);
+            std::thread::scope(|scope| {
+                for _ in 0..workers {
+                    let drawio_bin = &drawio_bin;
+                    let manifest = &manifest;
+                    let stale = &stale;
+                    let next_job = &next_job;
+                    scope.spawn(move || loop {
+                        let index = next_job.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let Some((key, directive, input, output, hash)) = stale.get(index) else {
+                            break;
+                        };
+                        if drawio_export(drawio_bin, input, directive, output).is_ok() {
+                            manifest.lock().unwrap().insert(key.clone(), hash.clone());
+                        } else {
+                            error!("Failed to export diagram for {input:?}");
+                        }
+                    });
+                }
+            });
+            save_manifest(&result_dir, &manifest.lock().unwrap());
         }
 
-        // How we process a chapter
-        fn process_chapter(ctx: &PreprocessorContext, ch: &mut Chapter) -> Result<(), Error> {
+        // Phase 3: cheap text-substitution pass, replacing each directive with a
+        // link/figure pointing at the now up-to-date rendered output.
+        //
+        // `embed_counter` is shared across the *whole* book, not reset per chapter: mdBook's
+        // HTML renderer concatenates every chapter into a single `print.html`, so two
+        // chapters that each embed a diagram at the same ordinal position would otherwise
+        // get identical embed suffixes (and therefore colliding scoped SVG ids) in that
+        // merged document.
+        fn substitute_item(
+            ctx: &PreprocessorContext,
+            item: &mut BookItem,
+            resolved: &Resolved,
+            embed_counter: &mut usize,
+        ) -> Result<(), Error> {
+            match item {
+                BookItem::Chapter(ch) => substitute_chapter(ctx, ch, resolved, embed_counter),
+                _ => Ok(()),
+            }
+        }
+
+        fn substitute_chapter(
+            ctx: &PreprocessorContext,
+            ch: &mut Chapter,
+            resolved: &Resolved,
+            embed_counter: &mut usize,
+        ) -> Result<(), Error> {
             let re: Regex = directive_regex();
             let cow = re.replace_all(&ch.content, |caps: &regex::Captures| {
-                process_match(ctx, &ch, caps)
+                *embed_counter += 1;
+                render_snippet(ctx, &ch, caps, *embed_counter, resolved)
             });
             ch.content = cow.into_owned();
             for sub in ch.sub_items.iter_mut() {
-                process_item(ctx, sub)?;
+                substitute_item(ctx, sub, resolved, embed_counter)?;
             }
             Ok(())
         }
 
+        let mut embed_counter = 0usize;
         for item in book.sections.iter_mut() {
-            process_item(ctx, item)?;
+            substitute_item(ctx, item, &resolved, &mut embed_counter)?;
         }
         Ok(book)
     }
@@ -127,27 +203,375 @@ fn get_drawio_bin(ctx: &PreprocessorContext) -> &str {
         .unwrap_or("drawio".into())
 }
 
+/// The number of diagrams to render concurrently.
+/// Can be set via [preprocessor.drawio.jobs] in book.toml; defaults to the number of
+/// available CPUs.
+fn get_jobs(ctx: &PreprocessorContext) -> usize {
+    ctx.config
+        .get("preprocessor.drawio.jobs")
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+}
+
+/// Whether diagrams should be embedded as inline SVG by default.
+/// Can be set via [preprocessor.drawio.embed] in book.toml; defaults to false. Can be
+/// overridden per-directive with `embed="true"`/`embed="false"`.
+fn get_embed_default(ctx: &PreprocessorContext) -> bool {
+    ctx.config
+        .get("preprocessor.drawio.embed")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// A single parsed `{{#drawio ...}}` directive.
+#[derive(Debug, Clone)]
+pub struct Directive {
+    pub path: String,
+    pub page: u32,
+    pub format: String,
+    pub scale: Option<String>,
+    pub width: Option<String>,
+    pub height: Option<String>,
+    pub caption: Option<String>,
+    pub alt: Option<String>,
+    pub embed: Option<bool>,
+}
+
+impl Directive {
+    /// Builds one [`Directive`] per page a regex match (produced by [`directive_regex`])
+    /// expands to - `page="1-3"`, `page="all"` and `page="1,4,5"` each resolve to more
+    /// than one. Returns an empty vec if the directive can't be parsed.
+    fn parse_many(ctx: &PreprocessorContext, caps: &regex::Captures) -> Vec<Directive> {
+        let Some(attrs) = caps.get(1) else {
+            return Vec::new();
+        };
+        let attrs = parse_attributes(attrs.as_str());
+
+        let Some(path) = attrs.get("path").cloned() else {
+            return Vec::new();
+        };
+        let Some(page_spec) = attrs.get("page") else {
+            return Vec::new();
+        };
+
+        let absolute_path = ctx.root.join(&path);
+        let pages = resolve_page_spec(page_spec, &absolute_path);
+        if pages.is_empty() {
+            return Vec::new();
+        }
+
+        let format = match attrs.get("format") {
+            Some(format) => match normalize_format(format) {
+                Some(format) => format,
+                None => {
+                    error!("Unsupported drawio format {format:?}, expected one of {ALLOWED_FORMATS:?}");
+                    return Vec::new();
+                }
+            },
+            None => "svg".to_string(),
+        };
+        let scale = attrs.get("scale").cloned();
+        let width = attrs.get("width").cloned();
+        let height = attrs.get("height").cloned();
+        let caption = attrs.get("caption").cloned();
+        let alt = attrs.get("alt").cloned();
+        let embed = attrs.get("embed").map(|v| v == "true");
+
+        // A multi-page directive renders several figures from the same caption/alt text;
+        // tag each with its page number so they stay distinguishable.
+        let multi_page = pages.len() > 1;
+        let tag_page = |text: &Option<String>, page: u32| {
+            text.as_ref()
+                .map(|text| if multi_page { format!("{text} (page {page})") } else { text.clone() })
+        };
+
+        pages
+            .into_iter()
+            .map(|page| Directive {
+                path: path.clone(),
+                page,
+                format: format.clone(),
+                scale: scale.clone(),
+                width: width.clone(),
+                height: height.clone(),
+                caption: tag_page(&caption, page),
+                alt: tag_page(&alt, page),
+                embed,
+            })
+            .collect()
+    }
+}
+
+/// Expands a directive's `page=` attribute into the concrete page numbers it selects:
+/// a single number (`"2"`), a range (`"1-3"`), a comma list (`"1,4,5"`), or `"all"` (every
+/// page in the source `.drawio` file, in order).
+fn resolve_page_spec(spec: &str, input: &Path) -> Vec<u32> {
+    let spec = spec.trim();
+
+    if spec.eq_ignore_ascii_case("all") {
+        return (1..=count_pages(input)).collect();
+    }
+
+    if let Some((start, end)) = spec.split_once('-') {
+        if let (Ok(start), Ok(end)) = (start.trim().parse::<u32>(), end.trim().parse::<u32>()) {
+            return (start..=end).collect();
+        }
+    }
+
+    if spec.contains(',') {
+        return spec
+            .split(',')
+            .filter_map(|page| page.trim().parse::<u32>().ok())
+            .collect();
+    }
+
+    spec.parse::<u32>().map_or_else(|_| Vec::new(), |page| vec![page])
+}
+
+/// Counts the pages in a `.drawio` file by counting its `<diagram>` elements, for
+/// `page="all"`. Defaults to 1 if the file can't be read.
+fn count_pages(input: &Path) -> u32 {
+    let Ok(contents) = std::fs::read_to_string(input) else {
+        debug!("  Could not read {input:?} to count pages, assuming 1");
+        return 1;
+    };
+    let re = Regex::new(r"<diagram\b").unwrap();
+    (re.find_iter(&contents).count() as u32).max(1)
+}
+
+/// Extracts `key="value"` (and bare `key=value`) pairs from the attribute portion of a
+/// drawio directive, e.g. `path="a.drawio" page=1 format="png"`.
+fn parse_attributes(attrs: &str) -> HashMap<String, String> {
+    let re = Regex::new(r#"(\w+)=(?:"([^"]*)"|(\S+))"#).unwrap();
+    re.captures_iter(attrs)
+        .map(|c| {
+            let value = c.get(2).or_else(|| c.get(3)).map(|m| m.as_str()).unwrap_or("");
+            (c[1].to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Resolves a [`Directive`] to the absolute path of its source `.drawio` file, the
+/// filename its rendered output is cached under, and the absolute path of that output.
+///
+/// The output filename encodes every render param (page, format, scale, width, height),
+/// not just page/format, so two directives for the same page that differ only in e.g.
+/// `scale` get distinct output files instead of colliding on the same cache entry.
+fn diagram_paths(ctx: &PreprocessorContext, directive: &Directive) -> (PathBuf, String, PathBuf) {
+    let absolute_path = ctx.root.join(&directive.path);
+    let diagram_name = absolute_path.file_stem().and_then(|s| s.to_str()).unwrap();
+
+    let mut output_name = format!("{}-page-{}", diagram_name, directive.page);
+    if let Some(scale) = &directive.scale {
+        output_name.push_str(&format!("-s{}", sanitize_filename_part(scale)));
+    }
+    if let Some(width) = &directive.width {
+        output_name.push_str(&format!("-w{}", sanitize_filename_part(width)));
+    }
+    if let Some(height) = &directive.height {
+        output_name.push_str(&format!("-h{}", sanitize_filename_part(height)));
+    }
+    output_name.push('.');
+    output_name.push_str(&directive.format);
+
+    let output_path = get_result_dir_abs(ctx).join(&output_name);
+    (absolute_path, output_name, output_path)
+}
+
+/// The export formats drawio-desktop is invoked with, and the only values `format=` may
+/// resolve to - anything else is rejected up front rather than making it into an output
+/// filename or a command-line argument.
+const ALLOWED_FORMATS: [&str; 4] = ["svg", "png", "pdf", "jpg"];
+
+/// Validates and normalizes a directive's `format=` value against [`ALLOWED_FORMATS`]
+/// (case-insensitively, `"jpeg"` accepted as an alias for `"jpg"`). Returns `None` for
+/// anything else instead of letting it flow unsanitized into a path or CLI argument.
+fn normalize_format(format: &str) -> Option<String> {
+    let lower = format.to_ascii_lowercase();
+    let lower = if lower == "jpeg" { "jpg".to_string() } else { lower };
+    ALLOWED_FORMATS.contains(&lower.as_str()).then_some(lower)
+}
+
+/// Sanitizes a directive param (e.g. `scale="1.5"`) for safe inclusion in a filename.
+///
+/// Bytes that are alphanumeric or `-` pass through unchanged; everything else (including a
+/// literal `_`) is hex-escaped as `_XX`. Collapsing every non-alphanumeric byte to a single
+/// `_` (as an earlier version of this function did) let distinct values collide - e.g.
+/// `"1.5"` and `"1_5"` both sanitized to `"1_5"` - which silently merged two directives'
+/// export jobs into one cache entry. Hex-escaping is unambiguous because `_` only ever
+/// appears as the start of a 3-byte escape sequence, never on its own.
+fn sanitize_filename_part(part: &str) -> String {
+    let mut out = String::with_capacity(part.len());
+    for byte in part.bytes() {
+        let c = byte as char;
+        if c.is_ascii_alphanumeric() || c == '-' {
+            out.push(c);
+        } else {
+            out.push_str(&format!("_{byte:02x}"));
+        }
+    }
+    out
+}
+
+/// Renders the Markdown/HTML snippet a directive should be replaced with, pointing at its
+/// (already rendered) output. Does not export the diagram itself - that happens up front
+/// in the preprocessor's parallel rendering phase.
+///
+/// A directive whose `page=` spans multiple pages (a range, a list, or `"all"`) expands
+/// into one snippet per resulting page, in order.
+///
+/// `embed_index` scopes element IDs when a page's SVG is embedded inline, so multiple
+/// diagrams on the same page don't collide; it is unique across the whole book, since
+/// mdBook's `print.html` merges every chapter into one document.
+///
+/// `resolved` is the cache Phase 1 populated while collecting export jobs; reused here so
+/// the directive isn't re-parsed (and its source file potentially re-read) a second time.
+fn render_snippet(ctx: &PreprocessorContext, ch: &Chapter, caps: &regex::Captures, embed_index: usize, resolved: &Resolved) -> String {
+    debug!("Processing regex match: {caps:?}");
+
+    let full_match = caps.get(0).map(|m| m.as_str()).unwrap_or_default();
+    let directives = match resolved.get(full_match) {
+        Some(directives) => directives.clone(),
+        None => Directive::parse_many(ctx, caps),
+    };
+    if directives.is_empty() {
+        error!("Failed to parse drawio directive: {caps:?}");
+        return caps.get(0).map(|m| m.as_str()).unwrap_or_default().to_string();
+    }
+
+    directives
+        .iter()
+        .enumerate()
+        .map(|(page_index, directive)| {
+            render_directive_snippet(ctx, ch, directive, &format!("{embed_index}-{page_index}"))
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Renders the snippet for a single already-resolved (single-page) [`Directive`].
+fn render_directive_snippet(ctx: &PreprocessorContext, ch: &Chapter, directive: &Directive, embed_suffix: &str) -> String {
+    debug!("  Directive: {directive:?}");
+
+    let (_, _, output_path) = diagram_paths(ctx, directive);
+
+    let embed = directive.embed.unwrap_or_else(|| get_embed_default(ctx));
+    if embed && directive.format == "svg" {
+        match embed_svg(&output_path, embed_suffix) {
+            Some(svg) => {
+                debug!("  Embedding inline SVG from {output_path:?}");
+                return match &directive.caption {
+                    Some(caption) => format!(
+                        "<figure>\n{svg}\n<figcaption>{}</figcaption>\n</figure>",
+                        escape_html(caption),
+                    ),
+                    None => svg,
+                };
+            }
+            None => error!("Failed to read exported SVG at {output_path:?} for embedding, falling back to a linked image"),
+        }
+    }
+
+    let output_relative_path = relative_path_from_chapter(ctx, ch, &output_path);
+    debug!("  Relative link from chapter: {output_relative_path:?}");
+
+    // A captioned <figure> when a caption was given, otherwise a plain Markdown image
+    // with meaningful alt text. `caption`/`alt` are free-form author-supplied text, so
+    // they're escaped for whichever syntax they're interpolated into rather than trusted
+    // verbatim - HTML escaping for the `<figure>` form, Markdown escaping for the `![]()`
+    // form.
+    let alt = directive.alt.as_deref().unwrap_or("Diagram");
+    let snippet = match &directive.caption {
+        Some(caption) => format!(
+            "<figure>\n<img src=\"{src}\" alt=\"{alt}\" />\n<figcaption>{caption}</figcaption>\n</figure>",
+            src = output_relative_path.display(),
+            alt = escape_html(alt),
+            caption = escape_html(caption),
+        ),
+        None => format!("![{}]({})", escape_markdown_alt(alt), output_relative_path.display()),
+    };
+    debug!("Produced snippet: {snippet}");
+    snippet
+}
+
+/// Escapes text for safe interpolation into an HTML attribute or element body (`&`, `<`,
+/// `>`, `"`), so a caption/alt containing those characters can't break out of the
+/// surrounding markup.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes text for safe interpolation into a Markdown `![alt](...)` image's alt text:
+/// HTML-escapes it (alt text is still rendered as HTML) and backslash-escapes `[`/`]` so
+/// it can't prematurely close the link label.
+fn escape_markdown_alt(text: &str) -> String {
+    escape_html(text).replace('[', "\\[").replace(']', "\\]")
+}
+
+/// Reads and sanitizes a generated SVG for inline embedding: strips the XML declaration
+/// and DOCTYPE (invalid inside an HTML/Markdown document) and scopes element IDs so
+/// multiple embeds on the same page don't collide.
+fn embed_svg(svg_path: &Path, embed_suffix: &str) -> Option<String> {
+    let raw = std::fs::read_to_string(svg_path).ok()?;
+
+    let xml_decl_re = Regex::new(r#"(?s)^\s*<\?xml.*?\?>"#).unwrap();
+    let doctype_re = Regex::new(r#"(?s)<!DOCTYPE[^>]*>"#).unwrap();
+    let without_xml_decl = xml_decl_re.replace(&raw, "");
+    let sanitized = doctype_re.replace(&without_xml_decl, "");
+
+    let suffix = format!("drawio-embed-{embed_suffix}");
+    Some(scope_svg_ids(sanitized.trim(), &suffix))
+}
+
+/// Rewrites every `id="..."` in an SVG (and the `#...` references to it in `href`/
+/// `xlink:href`/`url(#...)`) so it's unique to this embed.
+fn scope_svg_ids(svg: &str, suffix: &str) -> String {
+    let id_re = Regex::new(r#"\bid="([^"]+)""#).unwrap();
+    let mut scoped = svg.to_string();
+    for caps in id_re.captures_iter(svg) {
+        let id = &caps[1];
+        let unique_id = format!("{id}-{suffix}");
+        scoped = scoped.replace(&format!("id=\"{id}\""), &format!("id=\"{unique_id}\""));
+        scoped = scoped.replace(&format!("#{id}\""), &format!("#{unique_id}\""));
+        scoped = scoped.replace(&format!("#{id})"), &format!("#{unique_id})"));
+    }
+    scoped
+}
+
 /// Returns the regular expression used to match drawio directives in markdown files.
-/// Intended usage: {{#drawio path="path/to/diagram" page=1}}
+/// Intended usage: {{#drawio path="path/to/diagram" page=1 format="png" scale="2" caption="..." alt="..." embed="true"}}
+/// `page` also accepts a range (`page="1-3"`), a comma list (`page="1,4,5"`), or `page="all"`.
 pub fn directive_regex() -> Regex {
-    Regex::new(r#"\{\{#drawio\s+path=\"([^\"]+)\"\s+page=([0-9]+)[^}]*\}\}"#).unwrap()
+    Regex::new(r#"\{\{#drawio\s+([^}]*)\}\}"#).unwrap()
 }
 
-/// Invokes drawio to export a diagram to SVG format.
+/// Invokes drawio to export a diagram according to the given [`Directive`].
+///
+/// Takes the drawio binary name rather than the whole [`PreprocessorContext`] so it can be
+/// called from worker threads without sharing the (non-`Sync`) context across them.
 fn drawio_export(
-    ctx: &PreprocessorContext,
+    drawio_cmd: &str,
     input: &Path,
-    page: u32,
+    directive: &Directive,
     output_path: &Path,
 ) -> Result<(), Error> {
-    let cli_page = page.to_string();
-    let drawio_cmd = get_drawio_bin(ctx);
+    let cli_page = directive.page.to_string();
 
     debug!("Executing drawio command:");
     debug!("  Command: {drawio_cmd}");
     debug!("  Input file: {input:?}");
     debug!("  Output file: {output_path:?}");
     debug!("  Page: {cli_page}");
+    debug!("  Format: {format}", format = directive.format);
 
     let mut cmd = Command::new(drawio_cmd);
     cmd.env("ELECTRON_DISABLE_GPU", "1")
@@ -156,10 +580,21 @@ fn drawio_export(
         .arg("-p")
         .arg(&cli_page)
         .arg("-f")
-        .arg("svg")
+        .arg(&directive.format)
         .arg("-o")
-        .arg(&output_path)
-				.arg("--no-sandbox"); // Required for some CI environments
+        .arg(&output_path);
+
+    if let Some(scale) = &directive.scale {
+        cmd.arg("--scale").arg(scale);
+    }
+    if let Some(width) = &directive.width {
+        cmd.arg("--width").arg(width);
+    }
+    if let Some(height) = &directive.height {
+        cmd.arg("--height").arg(height);
+    }
+
+    cmd.arg("--no-sandbox"); // Required for some CI environments
 
     debug!("Full command: {cmd:?}");
 
@@ -234,37 +669,408 @@ fn format_time(time: SystemTime) -> String {
     chrono::DateTime::<chrono::Local>::from(time).format("%Y-%m-%d %H:%M:%S").to_string()
 }
 
-/// Determines if the output file needs to be regenerated based on modification times.
-/// Returns true if:
-/// - Output file doesn't exist
-/// - Input file is newer than output file
-fn should_generate(input: &Path, output: &Path) -> bool {
+/// The name of the sidecar manifest file that maps output filenames to the content hash
+/// of the input they were generated from. Stored alongside the exported diagrams so a
+/// fresh `git clone`/CI checkout (where every file's mtime is "now") doesn't force a
+/// full re-export.
+const MANIFEST_FILE_NAME: &str = "drawio-cache.json";
+
+/// Loads the content-hash manifest from the result directory. Returns an empty map if
+/// the manifest doesn't exist yet or fails to parse.
+fn load_manifest(result_dir: &Path) -> HashMap<String, String> {
+    let manifest_path = result_dir.join(MANIFEST_FILE_NAME);
+    let Ok(contents) = std::fs::read_to_string(&manifest_path) else {
+        debug!("  No cache manifest found at {manifest_path:?}, starting fresh");
+        return HashMap::new();
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        error!("Failed to parse cache manifest at {manifest_path:?}: {e}");
+        HashMap::new()
+    })
+}
+
+/// Persists the content-hash manifest to the result directory.
+fn save_manifest(result_dir: &Path, manifest: &HashMap<String, String>) {
+    let manifest_path = result_dir.join(MANIFEST_FILE_NAME);
+    match serde_json::to_string_pretty(manifest) {
+        Ok(json) => {
+            if let Err(e) = std::fs::write(&manifest_path, json) {
+                error!("Failed to write cache manifest to {manifest_path:?}: {e}");
+            }
+        }
+        Err(e) => error!("Failed to serialize cache manifest: {e}"),
+    }
+}
+
+/// Computes a content hash over the input `.drawio` bytes plus the directive params that
+/// affect the rendered output (page, format, scale, width, height), returned as hex.
+///
+/// Stored/compared as `{content_hash}-{params_hash}` so [`should_generate`]'s mtime
+/// fast-path can cheaply re-check the params half (no file read needed) without trusting
+/// a stale hash for a directive whose scale/width/height changed but whose source
+/// `.drawio` file didn't.
+fn compute_hash(input: &Path, directive: &Directive) -> Option<String> {
+    let content_hash = compute_content_hash(input)?;
+    Some(format!("{content_hash}-{}", compute_params_hash(directive)))
+}
+
+/// Hashes only the input `.drawio` bytes.
+fn compute_content_hash(input: &Path) -> Option<String> {
+    let bytes = std::fs::read(input).ok()?;
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// Hashes only the directive params that affect the rendered output (page, format, scale,
+/// width, height) - no file I/O, so it's cheap enough to always recompute.
+fn compute_params_hash(directive: &Directive) -> String {
+    let mut hasher = DefaultHasher::new();
+    directive.page.hash(&mut hasher);
+    directive.format.hash(&mut hasher);
+    directive.scale.hash(&mut hasher);
+    directive.width.hash(&mut hasher);
+    directive.height.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Determines whether the output needs to be (re)generated, using the content-hash
+/// manifest as the source of truth. Returns `(regenerate, hash)` where `hash` is the
+/// freshly computed hash to store in the manifest when generation succeeds.
+///
+/// The params half of the hash (page/format/scale/width/height) is always recomputed and
+/// compared - it's pure computation, no file I/O, so there's no cost to skip. The mtime
+/// fast-path only short-circuits re-*reading* the (potentially large) input file to check
+/// the content half: when the input hasn't been touched since the output was last
+/// generated, the content hash is trusted without re-reading the file.
+fn should_generate(
+    input: &Path,
+    output: &Path,
+    key: &str,
+    directive: &Directive,
+    manifest: &HashMap<String, String>,
+) -> (bool, String) {
     debug!("Checking if regeneration needed for {input:?} -> {output:?}");
 
-    // If output doesn't exist, we need to generate it
     if !output.exists() {
         debug!("  Output file does not exist");
-        return true;
+        return (true, compute_hash(input, directive).unwrap_or_default());
     }
 
-    let Ok(input_mtime) = std::fs::metadata(input).and_then(|m| m.modified()) else {
-        debug!("  Cannot read input metadata, regenerating.");
-        return true;
+    let mtime_unchanged = match (
+        std::fs::metadata(input).and_then(|m| m.modified()),
+        std::fs::metadata(output).and_then(|m| m.modified()),
+    ) {
+        (Ok(in_time), Ok(out_time)) => {
+            debug!("  Input modified at {}, output modified at {}",
+                   format_time(in_time), format_time(out_time));
+            in_time <= out_time
+        }
+        _ => false,
     };
 
-    let Ok(output_mtime) = std::fs::metadata(output).and_then(|m| m.modified()) else {
-        debug!("  Cannot read output metadata, regenerating.");
-        return true;
-    };
+    if let Some(cached_hash) = manifest.get(key) {
+        let params_hash = compute_params_hash(directive);
+        let params_unchanged = cached_hash.ends_with(&format!("-{params_hash}"));
 
-    // Regenerate if input is newer than output
-    if input_mtime > output_mtime {
-        debug!("  Input modified at {in_time} is newer than output modified at {out_time}",
-               in_time = format_time(input_mtime), out_time = format_time(output_mtime));
-        true
-    } else {
-        debug!("  Output is up-to-date (input: {in_time}, output: {out_time})",
-               in_time = format_time(input_mtime), out_time = format_time(output_mtime));
-        false
+        if mtime_unchanged && params_unchanged {
+            debug!("  mtime fast-path: input unchanged and render params unchanged, trusting cached hash");
+            return (false, cached_hash.clone());
+        }
+
+        let Some(hash) = compute_hash(input, directive) else {
+            debug!("  Cannot read input to hash, regenerating.");
+            return (true, String::new());
+        };
+        if &hash == cached_hash {
+            debug!("  Content hash unchanged ({hash}), skipping regeneration");
+            return (false, hash);
+        }
+        debug!("  Content hash changed ({cached_hash} -> {hash}), regenerating");
+        return (true, hash);
+    }
+
+    debug!("  No cached hash for {key}, regenerating");
+    (true, compute_hash(input, directive).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_page_spec_single_number() {
+        assert_eq!(resolve_page_spec("2", Path::new("unused.drawio")), vec![2]);
+    }
+
+    #[test]
+    fn resolve_page_spec_range() {
+        assert_eq!(resolve_page_spec("1-3", Path::new("unused.drawio")), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn resolve_page_spec_reversed_range_is_empty() {
+        assert_eq!(resolve_page_spec("3-1", Path::new("unused.drawio")), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn resolve_page_spec_comma_list() {
+        assert_eq!(resolve_page_spec("1,4,5", Path::new("unused.drawio")), vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn resolve_page_spec_comma_list_with_whitespace() {
+        assert_eq!(resolve_page_spec(" 1, 4 ,5", Path::new("unused.drawio")), vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn resolve_page_spec_comma_list_skips_invalid_entries() {
+        assert_eq!(resolve_page_spec("1,foo,3", Path::new("unused.drawio")), vec![1, 3]);
+    }
+
+    #[test]
+    fn resolve_page_spec_invalid_single_value_is_empty() {
+        assert_eq!(resolve_page_spec("not-a-page", Path::new("unused.drawio")), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn resolve_page_spec_all_counts_diagrams_in_file() {
+        let path = std::env::temp_dir().join("mdbook-drawio-test-resolve-page-spec-all.drawio");
+        std::fs::write(&path, "<mxfile><diagram/><diagram/><diagram/></mxfile>").unwrap();
+        assert_eq!(resolve_page_spec("all", &path), vec![1, 2, 3]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn resolve_page_spec_all_is_case_insensitive() {
+        let path = std::env::temp_dir().join("mdbook-drawio-test-resolve-page-spec-all-case.drawio");
+        std::fs::write(&path, "<mxfile><diagram/></mxfile>").unwrap();
+        assert_eq!(resolve_page_spec("ALL", &path), vec![1]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn resolve_page_spec_all_defaults_to_one_page_when_unreadable() {
+        let path = Path::new("/nonexistent/mdbook-drawio-test.drawio");
+        assert_eq!(resolve_page_spec("all", path), vec![1]);
+    }
+
+    #[test]
+    fn parse_attributes_quoted_and_bare_values() {
+        let attrs = parse_attributes(r#"path="a.drawio" page=1 format="png""#);
+        assert_eq!(attrs.get("path").map(String::as_str), Some("a.drawio"));
+        assert_eq!(attrs.get("page").map(String::as_str), Some("1"));
+        assert_eq!(attrs.get("format").map(String::as_str), Some("png"));
+    }
+
+    #[test]
+    fn parse_attributes_quoted_value_with_spaces() {
+        let attrs = parse_attributes(r#"path="a.drawio" caption="Some Diagram""#);
+        assert_eq!(attrs.get("caption").map(String::as_str), Some("Some Diagram"));
+    }
+
+    #[test]
+    fn parse_attributes_empty_quoted_value() {
+        let attrs = parse_attributes(r#"path="a.drawio" caption="""#);
+        assert_eq!(attrs.get("caption").map(String::as_str), Some(""));
+    }
+
+    #[test]
+    fn parse_attributes_ignores_unmatched_text() {
+        let attrs = parse_attributes(r#"path="a.drawio" garbage page=2"#);
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs.get("page").map(String::as_str), Some("2"));
+    }
+
+    #[test]
+    fn normalize_format_accepts_allow_list_case_insensitively() {
+        assert_eq!(normalize_format("PNG"), Some("png".to_string()));
+        assert_eq!(normalize_format("jpeg"), Some("jpg".to_string()));
+        assert_eq!(normalize_format("svg"), Some("svg".to_string()));
+    }
+
+    #[test]
+    fn normalize_format_rejects_unknown_and_path_traversal() {
+        assert_eq!(normalize_format("png/../../../tmp/evil"), None);
+        assert_eq!(normalize_format("bmp"), None);
+    }
+
+    #[test]
+    fn sanitize_filename_part_does_not_collide_on_punctuation() {
+        assert_ne!(sanitize_filename_part("1.5"), sanitize_filename_part("1_5"));
+        assert_ne!(sanitize_filename_part("a b"), sanitize_filename_part("a-b"));
+    }
+
+    #[test]
+    fn sanitize_filename_part_keeps_alphanumerics_and_dashes_as_is() {
+        assert_eq!(sanitize_filename_part("abc-123"), "abc-123");
+    }
+
+    #[test]
+    fn scope_svg_ids_scopes_definitions_and_their_references() {
+        let svg = "<svg><defs><linearGradient id=\"grad\"/></defs><rect fill=\"url(#grad)\"/><use href=\"#grad\"/></svg>";
+        let scoped = scope_svg_ids(svg, "embed-a");
+        assert!(scoped.contains("id=\"grad-embed-a\""));
+        assert!(scoped.contains("url(#grad-embed-a)"));
+        assert!(scoped.contains("href=\"#grad-embed-a\""));
+        assert!(!scoped.contains("id=\"grad\""));
+    }
+
+    #[test]
+    fn scope_svg_ids_keeps_two_embeds_with_the_same_source_id_distinct() {
+        let svg = "<svg><defs><linearGradient id=\"grad\"/></defs><rect fill=\"url(#grad)\"/></svg>";
+        let first = scope_svg_ids(svg, "drawio-embed-1-0");
+        let second = scope_svg_ids(svg, "drawio-embed-2-0");
+        assert_ne!(first, second);
+        assert!(first.contains("id=\"grad-drawio-embed-1-0\""));
+        assert!(second.contains("id=\"grad-drawio-embed-2-0\""));
+    }
+
+    #[test]
+    fn embed_svg_strips_xml_decl_and_doctype_and_scopes_ids() {
+        let path = std::env::temp_dir().join("mdbook-drawio-test-embed-svg.svg");
+        std::fs::write(
+            &path,
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\">\n<svg><rect id=\"r\"/></svg>",
+        )
+        .unwrap();
+        let embedded = embed_svg(&path, "1-0").unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert!(!embedded.contains("<?xml"));
+        assert!(!embedded.contains("<!DOCTYPE"));
+        assert!(embedded.contains("id=\"r-drawio-embed-1-0\""));
+    }
+
+    #[test]
+    fn embed_svg_returns_none_when_file_is_missing() {
+        let path = Path::new("/nonexistent/mdbook-drawio-test-missing.svg");
+        assert!(embed_svg(path, "1-0").is_none());
+    }
+
+    fn test_directive(scale: Option<&str>) -> Directive {
+        Directive {
+            path: "unused.drawio".to_string(),
+            page: 1,
+            format: "svg".to_string(),
+            scale: scale.map(str::to_string),
+            width: None,
+            height: None,
+            caption: None,
+            alt: None,
+            embed: None,
+        }
+    }
+
+    #[test]
+    fn should_generate_regenerates_when_output_missing() {
+        let input = std::env::temp_dir().join("mdbook-drawio-test-should-generate-no-output.drawio");
+        std::fs::write(&input, "content").unwrap();
+        let output = std::env::temp_dir().join("mdbook-drawio-test-should-generate-no-output.svg");
+        std::fs::remove_file(&output).ok();
+
+        let (regenerate, _) = should_generate(&input, &output, "key", &test_directive(None), &HashMap::new());
+        assert!(regenerate);
+
+        std::fs::remove_file(&input).ok();
+    }
+
+    #[test]
+    fn should_generate_mtime_fast_path_trusts_cache_when_unchanged() {
+        let input = std::env::temp_dir().join("mdbook-drawio-test-should-generate-fast-path.drawio");
+        let output = std::env::temp_dir().join("mdbook-drawio-test-should-generate-fast-path.svg");
+        std::fs::write(&input, "content").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&output, "rendered").unwrap();
+
+        let directive = test_directive(None);
+        let cached_hash = compute_hash(&input, &directive).unwrap();
+        let manifest = HashMap::from([("key".to_string(), cached_hash.clone())]);
+
+        let (regenerate, hash) = should_generate(&input, &output, "key", &directive, &manifest);
+        assert!(!regenerate);
+        assert_eq!(hash, cached_hash);
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn should_generate_regenerates_when_params_changed_even_if_mtime_unchanged() {
+        let input = std::env::temp_dir().join("mdbook-drawio-test-should-generate-params-changed.drawio");
+        let output = std::env::temp_dir().join("mdbook-drawio-test-should-generate-params-changed.svg");
+        std::fs::write(&input, "content").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&output, "rendered").unwrap();
+
+        // Cached hash was computed for a directive with a different `scale` - the mtime
+        // fast-path must not trust it just because the file itself hasn't changed.
+        let cached_hash = compute_hash(&input, &test_directive(Some("1"))).unwrap();
+        let manifest = HashMap::from([("key".to_string(), cached_hash)]);
+
+        let (regenerate, _) = should_generate(&input, &output, "key", &test_directive(Some("2")), &manifest);
+        assert!(regenerate);
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn should_generate_skips_regeneration_via_content_hash_despite_stale_mtime() {
+        let input = std::env::temp_dir().join("mdbook-drawio-test-should-generate-stale-mtime.drawio");
+        let output = std::env::temp_dir().join("mdbook-drawio-test-should-generate-stale-mtime.svg");
+        std::fs::write(&output, "rendered").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&input, "content").unwrap();
+
+        let directive = test_directive(None);
+        let cached_hash = compute_hash(&input, &directive).unwrap();
+        let manifest = HashMap::from([("key".to_string(), cached_hash.clone())]);
+
+        let (regenerate, hash) = should_generate(&input, &output, "key", &directive, &manifest);
+        assert!(!regenerate);
+        assert_eq!(hash, cached_hash);
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn should_generate_regenerates_when_content_hash_changed() {
+        let input = std::env::temp_dir().join("mdbook-drawio-test-should-generate-content-changed.drawio");
+        let output = std::env::temp_dir().join("mdbook-drawio-test-should-generate-content-changed.svg");
+        std::fs::write(&input, "old content").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&output, "rendered").unwrap();
+
+        let directive = test_directive(None);
+        let cached_hash = compute_hash(&input, &directive).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&input, "new content").unwrap();
+        let manifest = HashMap::from([("key".to_string(), cached_hash)]);
+
+        let (regenerate, _) = should_generate(&input, &output, "key", &directive, &manifest);
+        assert!(regenerate);
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn compute_params_hash_differs_when_scale_differs() {
+        assert_ne!(
+            compute_params_hash(&test_directive(Some("1"))),
+            compute_params_hash(&test_directive(Some("2"))),
+        );
+    }
+
+    #[test]
+    fn escape_html_escapes_special_characters() {
+        assert_eq!(escape_html(r#"<a href="x">Tom & Jerry</a>"#), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&lt;/a&gt;");
+    }
+
+    #[test]
+    fn escape_markdown_alt_escapes_brackets() {
+        assert_eq!(escape_markdown_alt("a [link] like"), "a \\[link\\] like");
     }
 }